@@ -9,25 +9,74 @@ use flash_algorithm::*;
 // use ch32v3::ch32v30x as pac;
 use ch32_metapac::{FLASH, flash::regs::{Addr, Keyr, Modekeyr}};
 
-struct Algorithm;
+struct Algorithm {
+    function: Function,
+}
 
 const FLASH_KEY1: u32 = 0x45670123;
 const FLASH_KEY2: u32 = 0xCDEF89AB;
 
 const ERASE_TIMEOUT: u32 = 0xF00000;
 
-algorithm!(Algorithm, {
+// Each erased word actually reads back as 0xE339E339, not a byte-repeated
+// 0x39 pattern, so blank detection has to compare whole words rather than
+// relying on `empty_value` byte-wise.
+const ERASED_WORD: u32 = 0xE339E339;
+
+// Set to `false` to skip the post-program/post-erase readback verify.
+const VERIFY_AFTER_PROGRAM: bool = true;
+
+// Base address of the main flash array.
+const FLASH_BASE: u32 = 0x0800_0000;
+
+// Per-device flash geometry. No Cargo.toml yet for a per-device feature, so
+// the target part is chosen by the GEOMETRY const below instead.
+struct Geometry {
+    device_name: &'static str,
+    flash_size: u32,
+    sector_size: u32,
+}
+
+const CH32V208: Geometry = Geometry {
     device_name: "ch32v208",
+    flash_size: 0x1_0000,
+    sector_size: 0x8000,
+};
+
+// UNVERIFIED PLACEHOLDER: flash_size matches the V307 datasheet, but
+// sector_size is just copied from CH32V208 and has not been checked against
+// the V307 reference manual. Do not wire this into GEOMETRY until that's
+// confirmed — an unverified erase granularity silently breaks
+// erase_sector's alignment check and the region size passed to blank_check.
+#[allow(dead_code)]
+const CH32V307: Geometry = Geometry {
+    device_name: "ch32v307",
+    flash_size: 0x4_0000,
+    sector_size: 0x8000,
+};
+
+// The part this build targets. See the warning on CH32V307 before swapping
+// it in here.
+const GEOMETRY: Geometry = CH32V208;
+
+const SECTOR_MASK: u32 = GEOMETRY.sector_size - 1;
+// Quick Program Mode page size, fixed by the FPEC hardware.
+const PAGE_SIZE: u32 = 0x100;
+const PAGE_MASK: u32 = PAGE_SIZE - 1;
+
+algorithm!(Algorithm, {
+    device_name: GEOMETRY.device_name,
     device_type: DeviceType::Onchip,
     flash_address: 0x0000_0000,
-    flash_size: 0x10000,
-    page_size: 0x100,
-    // Note: This is not correct, each erased word looks like: 0xe339e339
+    flash_size: GEOMETRY.flash_size,
+    page_size: PAGE_SIZE,
+    // Necessarily wrong: an erased word reads as 0xE339E339, not a repeated
+    // byte. erase_all/erase_sector verify blankness themselves instead.
     empty_value: 0x39,
     program_time_out: 1000,
     erase_time_out: 2000,
     sectors: [{
-        size: 0x8000,
+        size: GEOMETRY.sector_size,
         address: 0x0000000,
     }]
 });
@@ -64,6 +113,29 @@ impl From<Error> for ErrorCode {
     }
 }
 
+// `addr` is already translated to the memory-mapped address.
+fn is_blank(addr: u32, len: u32) -> bool {
+    (addr..addr + len)
+        .step_by(4)
+        .all(|addr| unsafe { (addr as *const u32).read_volatile() } == ERASED_WORD)
+}
+
+// `base` is already translated to the memory-mapped address.
+fn read_translated(base: u32, data: &mut [u8]) {
+    let mut chunks = data.chunks_exact_mut(4);
+    for (chunk, word_addr) in (&mut chunks).zip((base..).step_by(4)) {
+        let word = unsafe { (word_addr as *const u32).read_volatile() };
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+
+    let tail = chunks.into_remainder();
+    if !tail.is_empty() {
+        let word_addr = base + (data.len() - tail.len()) as u32;
+        let word = unsafe { (word_addr as *const u32).read_volatile() };
+        tail.copy_from_slice(&word.to_le_bytes()[..tail.len()]);
+    }
+}
+
 fn wait_until_not_write_busy() -> Result<(), ErrorCode> {
     for _ in 0..ERASE_TIMEOUT {
         let status = FLASH.statr().read();
@@ -95,8 +167,127 @@ fn wait_until_not_busy() -> Result<(), ErrorCode> {
     Err(Error::EraseTimeout.into())
 }
 
+impl Algorithm {
+    // `addr` is relative to `flash_address`.
+    pub fn blank_check(addr: u32, len: u32) -> bool {
+        is_blank(addr + FLASH_BASE, len)
+    }
+
+    // `addr` is relative to `flash_address`.
+    fn read(addr: u32, data: &mut [u8]) {
+        read_translated(addr + FLASH_BASE, data)
+    }
+
+    // Reads back the page at `base` (already translated, same convention as
+    // program_page_fast/program_words) and compares it against `data`
+    // instead of programming it.
+    //
+    // TODO(unconfirmed): assumes probe-rs drives Function::Verify by still
+    // calling program_page per chunk, rather than verifying by reading
+    // target memory directly over the debug link. Check against the
+    // flash-algorithm/probe-rs source before relying on this.
+    fn verify_page(&mut self, base: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        let mut actual = [0u8; PAGE_SIZE as usize];
+        let actual = &mut actual[..data.len()];
+        read_translated(base, actual);
+        if actual == data {
+            Ok(())
+        } else {
+            Err(Error::VerificationError.into())
+        }
+    }
+
+    /// Programs a full, page-aligned 256-byte page via Quick Program Mode.
+    /// `addr` is already translated to the flash's memory-mapped address.
+    fn program_page_fast(&mut self, addr: Addr, data: &[u8]) -> Result<(), ErrorCode> {
+        FLASH.ctlr().modify(|w| w.set_page_pg(true));
+        wait_until_not_busy()?;
+
+        for (word, addr) in data.chunks_exact(4).zip((addr.0..).step_by(4)) {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            unsafe {
+                (addr as *mut u32).write_volatile(word);
+                wait_until_not_write_busy()?;
+            };
+        }
+
+        FLASH.ctlr().modify(|w| w.set_pgstart(true));
+        wait_until_not_busy()?;
+        FLASH.ctlr().modify(|w| w.set_page_pg(false));
+
+        if VERIFY_AFTER_PROGRAM {
+            for (word, addr) in data.chunks_exact(4).zip((addr.0..).step_by(4)) {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                let programmed = unsafe { (addr as *const u32).read_volatile() };
+                if programmed != word {
+                    return Err(Error::VerificationError.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Programs an arbitrarily sized, word-aligned span via standard
+    /// programming (the `pg` bit), one 32-bit word at a time. Used as the
+    /// fallback for buffers that don't fill a whole Quick Program page.
+    ///
+    /// A trailing 1-3 byte tail that doesn't fill a full word is merged into
+    /// the surrounding (erased) word before being written, so the caller
+    /// doesn't need to pad its buffer out to a word boundary.
+    fn program_words_standard(&mut self, addr: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        if addr & 0x3 != 0 {
+            return Err(Error::InvalidAddress.into());
+        }
+
+        FLASH.ctlr().modify(|w| w.set_pg(true));
+        let result = Self::program_words(addr, data);
+        FLASH.ctlr().modify(|w| w.set_pg(false));
+        result
+    }
+
+    /// Does the actual word-by-word programming for
+    /// [`Algorithm::program_words_standard`], assuming `pg` is already set.
+    /// Pulled out so the caller can unconditionally clear `pg` on every exit
+    /// path, including the `?`-propagated timeout/programming errors below.
+    fn program_words(addr: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        wait_until_not_busy()?;
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let word_addr = addr + offset as u32;
+            let remaining = &data[offset..];
+
+            let word = if remaining.len() >= 4 {
+                u32::from_le_bytes(remaining[..4].try_into().unwrap())
+            } else {
+                let existing = unsafe { (word_addr as *const u32).read_volatile() };
+                let mut bytes = existing.to_le_bytes();
+                bytes[..remaining.len()].copy_from_slice(remaining);
+                u32::from_le_bytes(bytes)
+            };
+
+            unsafe {
+                (word_addr as *mut u32).write_volatile(word);
+            }
+            wait_until_not_write_busy()?;
+
+            if VERIFY_AFTER_PROGRAM {
+                let programmed = unsafe { (word_addr as *const u32).read_volatile() };
+                if programmed != word {
+                    return Err(Error::VerificationError.into());
+                }
+            }
+
+            offset += remaining.len().min(4);
+        }
+
+        Ok(())
+    }
+}
+
 impl FlashAlgorithm for Algorithm {
-    fn new(_address: u32, _clock: u32, _function: Function) -> Result<Self, ErrorCode> {
+    fn new(_address: u32, _clock: u32, function: Function) -> Result<Self, ErrorCode> {
         // Unlock the flash
         FLASH.keyr().write_value(Keyr(FLASH_KEY1));
         FLASH.keyr().write_value(Keyr(FLASH_KEY2));
@@ -105,52 +296,65 @@ impl FlashAlgorithm for Algorithm {
         FLASH.modekeyr().write_value(Modekeyr(FLASH_KEY1));
         FLASH.modekeyr().write_value(Modekeyr(FLASH_KEY2));
 
-        Ok(Self)
+        Ok(Self { function })
+    }
+
+    fn erase_all(&mut self) -> Result<(), ErrorCode> {
+        wait_until_not_busy()?;
+
+        FLASH.ctlr().modify(|w| w.set_mer(true));
+        FLASH.ctlr().modify(|w| w.set_strt(true));
+        wait_until_not_busy()?;
+        FLASH.ctlr().modify(|w| w.set_mer(false));
+
+        if VERIFY_AFTER_PROGRAM && !Self::blank_check(0, GEOMETRY.flash_size) {
+            return Err(Error::VerificationError.into());
+        }
+
+        Ok(())
     }
 
     fn erase_sector(&mut self, addr: u32) -> Result<(), ErrorCode> {
-        let addr = addr + 0x0800_0000;
-        if addr & 0x7FFF != 0 {
+        if addr & SECTOR_MASK != 0 {
             return Err(Error::InvalidAddress.into());
         }
-        let addr = Addr(addr);
+
         wait_until_not_busy()?;
 
         FLASH.ctlr().modify(|w| w.set_ber32(true));
-        FLASH.addr().write_value(addr);
+        FLASH.addr().write_value(Addr(addr + FLASH_BASE));
         FLASH.ctlr().modify(|w| w.set_strt(true));
         wait_until_not_busy()?;
         FLASH.ctlr().modify(|w| w.set_ber32(false));
+
+        if VERIFY_AFTER_PROGRAM && !Self::blank_check(addr, GEOMETRY.sector_size) {
+            return Err(Error::VerificationError.into());
+        }
+
         Ok(())
     }
 
     fn program_page(&mut self, addr: u32, data: &[u8]) -> Result<(), ErrorCode> {
-        let addr = addr + 0x0800_0000;
+        let addr = addr + FLASH_BASE;
+
+        if self.function == Function::Verify {
+            return self.verify_page(addr, data);
+        }
+
         let ctlr = FLASH.ctlr().read();
         if ctlr.lock() || ctlr.flock() {
             return Err(Error::FlashLocked.into());
         }
-        if addr & 0xFF != 0 {
-            return Err(Error::InvalidAddress.into());
-        }
-        let addr = Addr(addr);
-
-        FLASH.ctlr().modify(|w| w.set_page_pg(true));
-        wait_until_not_busy()?;
 
-        for (word, addr) in data.chunks_exact(4).zip((addr.0..).step_by(4)) {
-            let word = u32::from_le_bytes(word.try_into().unwrap());
-            unsafe {
-                (addr as *mut u32).write_volatile(word);
-                wait_until_not_write_busy()?;
-            };
+        // Quick Program Mode only programs whole, page-aligned 256-byte
+        // pages. Anything smaller or unaligned (the tail of an image, a
+        // partial sector rewrite, ...) falls back to standard word-at-a-time
+        // programming below.
+        if addr & PAGE_MASK == 0 && data.len() == PAGE_SIZE as usize {
+            self.program_page_fast(Addr(addr), data)
+        } else {
+            self.program_words_standard(addr, data)
         }
-
-        FLASH.ctlr().modify(|w| w.set_pgstart(true));
-        wait_until_not_busy()?;
-        FLASH.ctlr().modify(|w| w.set_page_pg(false));
-
-        Ok(())
     }
 }
 